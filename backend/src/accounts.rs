@@ -0,0 +1,165 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Default location for the persistent account database, created on first
+/// run if it doesn't already exist.
+pub const DEFAULT_ACCOUNTS_DB_URL: &str = "sqlite://accounts.db?mode=rwc";
+
+/// Reasons an account operation can fail, surfaced to the client as a
+/// `WebSocketMessage::Error`. Never carries the password or hash.
+///
+/// `NotFound` and `InvalidPassword` are kept as distinct variants for
+/// internal logging, but deliberately render to the same `Display` message:
+/// telling a client which one happened would let them enumerate registered
+/// usernames without knowing any password.
+#[derive(Debug)]
+pub enum AccountError {
+    UsernameTaken,
+    NotFound,
+    InvalidPassword,
+    Storage,
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AccountError::UsernameTaken => "Username already exists",
+            AccountError::NotFound | AccountError::InvalidPassword => "Invalid username or password",
+            AccountError::Storage => "Account storage error",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Persistent, password-protected account directory backed by SQLite, so a
+/// username survives restarts and can't be reclaimed by a different client
+/// without the original password.
+#[derive(Clone)]
+pub struct AccountStore {
+    pool: SqlitePool,
+}
+
+impl AccountStore {
+    /// Open (creating if needed) the SQLite database at `database_url`. The
+    /// pool connects lazily on first use, so this doesn't need to be async.
+    pub fn connect_lazy(database_url: &str) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            pool: SqlitePoolOptions::new().connect_lazy(database_url)?,
+        })
+    }
+
+    /// Create the accounts table if it doesn't already exist
+    pub async fn init(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                username TEXT PRIMARY KEY,
+                argon2_hash TEXT NOT NULL,
+                public_key TEXT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Claim `username` with an Argon2id-hashed password (a fresh salt per
+    /// user, default params of ~19 MiB / 2 iterations), rejecting the call
+    /// if the name is already taken.
+    pub async fn create_account(
+        &self,
+        username: &str,
+        password: &str,
+        public_key: &str,
+    ) -> Result<(), AccountError> {
+        let existing: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM accounts WHERE username = ?")
+            .bind(username)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|_| AccountError::Storage)?;
+
+        if existing > 0 {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|_| AccountError::Storage)?
+            .to_string();
+
+        sqlx::query("INSERT INTO accounts (username, argon2_hash, public_key) VALUES (?, ?, ?)")
+            .bind(username)
+            .bind(&argon2_hash)
+            .bind(public_key)
+            .execute(&self.pool)
+            .await
+            .map_err(|_| AccountError::Storage)?;
+
+        Ok(())
+    }
+
+    /// Whether `username` has a persistent account, independent of whether
+    /// they're currently connected. Used to decide whether a message to an
+    /// offline recipient should still be queued for them (a real, if absent,
+    /// account) or rejected outright (no such user at all).
+    pub async fn account_exists(&self, username: &str) -> Result<bool, AccountError> {
+        let existing: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM accounts WHERE username = ?")
+            .bind(username)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|_| AccountError::Storage)?;
+
+        Ok(existing > 0)
+    }
+
+    /// Verify `password` against the stored hash for `username`, using
+    /// Argon2's constant-time comparison so a mismatch can't be timed apart
+    /// from a match. An unknown username runs the same Argon2 verification
+    /// against a dummy hash rather than short-circuiting, so the two
+    /// failure modes (no such account vs. wrong password) - which
+    /// `AccountError`'s `Display` already collapses into one message - can't
+    /// be told apart by response timing either.
+    pub async fn verify_password(&self, username: &str, password: &str) -> Result<(), AccountError> {
+        let row: Option<String> = sqlx::query_scalar("SELECT argon2_hash FROM accounts WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|_| AccountError::Storage)?;
+
+        let stored_hash = match row {
+            Some(stored_hash) => stored_hash,
+            None => {
+                let dummy = PasswordHash::new(dummy_hash()).map_err(|_| AccountError::Storage)?;
+                let _ = Argon2::default().verify_password(password.as_bytes(), &dummy);
+                return Err(AccountError::NotFound);
+            }
+        };
+
+        let parsed_hash = PasswordHash::new(&stored_hash).map_err(|_| AccountError::Storage)?;
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .map_err(|_| AccountError::InvalidPassword)
+    }
+}
+
+/// A fixed Argon2id hash with no corresponding real account, generated once
+/// per process and reused for every `verify_password` call against an
+/// unknown username so that branch costs the same Argon2 verification as a
+/// real one.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"dummy-password-for-timing-only", &salt)
+            .expect("hashing a fixed dummy password cannot fail")
+            .to_string()
+    })
+}
@@ -1,16 +1,21 @@
-use axum::extract::ws::Message;
 use std::{
     collections::HashMap,
     sync::Arc,
 };
 use tokio::sync::RwLock;
 use tracing::error;
+use uuid::Uuid;
 
-/// Manages active WebSocket connections
+use crate::transport::Frame;
+
+/// Manages active signaling connections, over whichever transport carried
+/// them in
 #[derive(Clone)]
 pub struct ConnectionManager {
-    /// Map username to their WebSocket sender
-    connections: Arc<RwLock<HashMap<String, tokio::sync::mpsc::UnboundedSender<Message>>>>,
+    /// Map username to the senders for every device they're connected from,
+    /// each keyed by its own connection id so one device can disconnect
+    /// without disturbing the others.
+    connections: Arc<RwLock<HashMap<String, Vec<(Uuid, tokio::sync::mpsc::UnboundedSender<Frame>)>>>>,
 }
 
 impl ConnectionManager {
@@ -20,51 +25,90 @@ impl ConnectionManager {
         }
     }
 
-    /// Register a new connection
-    pub async fn register(&self, username: String, sender: tokio::sync::mpsc::UnboundedSender<Message>) {
+    /// Register a new connection for a user, returning the id of this
+    /// connection (needed to remove just this device later)
+    pub async fn register(&self, username: String, sender: tokio::sync::mpsc::UnboundedSender<Frame>) -> Uuid {
+        let connection_id = Uuid::new_v4();
         let mut connections = self.connections.write().await;
-        connections.insert(username, sender);
+        connections
+            .entry(username)
+            .or_insert_with(Vec::new)
+            .push((connection_id, sender));
+        connection_id
     }
 
-    /// Remove a connection
-    pub async fn remove(&self, username: &str) {
+    /// Remove a single device's connection, returning `true` if the user has
+    /// no other live connections left
+    pub async fn remove(&self, username: &str, connection_id: Uuid) -> bool {
         let mut connections = self.connections.write().await;
-        connections.remove(username);
+        let Some(devices) = connections.get_mut(username) else {
+            return true;
+        };
+
+        devices.retain(|(id, _)| *id != connection_id);
+
+        if devices.is_empty() {
+            connections.remove(username);
+            true
+        } else {
+            false
+        }
     }
 
-    /// Send message to a specific user
-    pub async fn send_to_user(&self, username: &str, message: Message) -> Result<(), String> {
+    /// Send a frame to every device a user is connected from
+    pub async fn send_to_user(&self, username: &str, frame: Frame) -> Result<(), String> {
         let connections = self.connections.read().await;
-        if let Some(sender) = connections.get(username) {
-            sender.send(message).map_err(|_| "Failed to send message".to_string())
+        let Some(devices) = connections.get(username) else {
+            return Err("User not connected".to_string());
+        };
+
+        let mut delivered = false;
+        for (_, sender) in devices {
+            if sender.send(frame.clone()).is_ok() {
+                delivered = true;
+            }
+        }
+
+        if delivered {
+            Ok(())
         } else {
-            Err("User not connected".to_string())
+            Err("Failed to send message".to_string())
         }
     }
 
-    /// Broadcast message to all connected users
-    /// Note: Currently only supports Text messages (which is what we use)
-    pub async fn broadcast(&self, message: Message) {
-        // Extract text content if it's a Text message
-        let text_content = match &message {
-            Message::Text(text) => text.clone(),
+    /// Broadcast a frame to all connected users, on every device
+    /// Note: Currently only supports Text frames (which is what we use)
+    pub async fn broadcast(&self, frame: Frame) {
+        // Extract text content if it's a Text frame
+        let text_content = match &frame {
+            Frame::Text(text) => text.clone(),
             _ => {
-                // For non-text messages, we don't broadcast
-                // (we only use Text messages in our protocol)
+                // For non-text frames, we don't broadcast
+                // (we only use Text frames in our protocol)
                 return;
             }
         };
-        
+
         let connections = self.connections.read().await;
-        
-        // Send cloned text message to each connected user
-        for (username, sender) in connections.iter() {
-            if let Err(e) = sender.send(Message::Text(text_content.clone())) {
-                error!("Failed to send to {}: {:?}", username, e);
+
+        // Send cloned text frame to each device of each connected user
+        for (username, devices) in connections.iter() {
+            for (_, sender) in devices {
+                if let Err(e) = sender.send(Frame::Text(text_content.clone())) {
+                    error!("Failed to send to {}: {:?}", username, e);
+                }
             }
         }
     }
 
+    /// Remove every device a user is connected from (used when a user is
+    /// evicted outright, e.g. by the presence sweeper, rather than a single
+    /// device disconnecting)
+    pub async fn remove_all(&self, username: &str) {
+        let mut connections = self.connections.write().await;
+        connections.remove(username);
+    }
+
     /// Get list of connected usernames
     #[allow(dead_code)] // May be used for future features or debugging
     pub async fn get_connected_users(&self) -> Vec<String> {
@@ -73,3 +117,54 @@ impl ConnectionManager {
     }
 }
 
+/// RAII handle for a registered WebSocket connection. Dropping it - whether
+/// the socket closed cleanly, errored, or the handling task panicked -
+/// removes this device from the `ConnectionManager`, and once a user's last
+/// device disconnects, clears their entries in `AppState`, so cleanup never
+/// depends on every exit path remembering to call `remove` itself.
+pub struct ConnectionGuard {
+    username: String,
+    connection_id: Uuid,
+    connection_manager: ConnectionManager,
+    state: crate::models::AppState,
+}
+
+impl ConnectionGuard {
+    pub fn new(
+        username: String,
+        connection_id: Uuid,
+        connection_manager: ConnectionManager,
+        state: crate::models::AppState,
+    ) -> Self {
+        Self {
+            username,
+            connection_id,
+            connection_manager,
+            state,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let username = self.username.clone();
+        let connection_id = self.connection_id;
+        let connection_manager = self.connection_manager.clone();
+        let state = self.state.clone();
+
+        tokio::spawn(async move {
+            let was_last_device = connection_manager.remove(&username, connection_id).await;
+            if !was_last_device {
+                return;
+            }
+
+            // Shared with the presence sweeper's eviction path: clears
+            // `users`/`signaling`/`pending_messages`/`connections`/
+            // `usernames`, drops this user from any room they were still in
+            // (rather than leaving a ghost member), and rebroadcasts the
+            // roster.
+            state.forget_user(&username).await;
+            tracing::info!("User disconnected: {}", username);
+        });
+    }
+}
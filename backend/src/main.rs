@@ -1,4 +1,5 @@
 use axum::{
+    extract::ws::WebSocketUpgrade,
     extract::State,
     response::IntoResponse,
     routing::{get, post},
@@ -8,13 +9,20 @@ use std::net::SocketAddr;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::info;
 
+mod accounts;
+mod connection_manager;
 mod handlers;
 mod models;
+mod rooms;
 mod signaling;
+mod transport;
+mod websocket;
+mod webtransport;
 
 use handlers::*;
 use models::*;
 use signaling::{get_pending_messages, *};
+use websocket::handle_websocket;
 
 #[tokio::main]
 async fn main() {
@@ -28,6 +36,19 @@ async fn main() {
     sodiumoxide::init().expect("Failed to initialize libsodium");
 
     let state = AppState::new();
+    state
+        .accounts
+        .init()
+        .await
+        .expect("Failed to initialize account database");
+
+    // The WebTransport (HTTP/3) signaling endpoint runs on its own UDP
+    // socket alongside the axum server, driving the same connection loop
+    // `ws_handler` uses for WebSocket clients.
+    let webtransport_state = state.clone();
+    tokio::spawn(async move {
+        webtransport::serve(webtransport_state).await;
+    });
 
     // Build router - WebRTC signaling server
     let app = Router::new()
@@ -35,13 +56,17 @@ async fn main() {
         .route("/api/user/public-key", post(get_public_key))
         .route("/api/register", post(register_user))
         .route("/api/check-username", post(check_username))
+        .route("/api/heartbeat", post(heartbeat))
         .route("/api/online-users", get(get_online_users))
+        .route("/api/search-users", get(search_users))
         .route("/api/webrtc/offer", post(handle_offer))
         .route("/api/webrtc/answer", post(handle_answer))
         .route("/api/webrtc/ice-candidate", post(handle_ice_candidate))
-        .route("/api/webrtc/pending-messages/:username", get(|State(state): State<AppState>, axum::extract::Path(username): axum::extract::Path<String>| async move {
-            get_pending_messages(State(state), username).await
+        .route("/api/message", post(relay_message))
+        .route("/api/webrtc/pending-messages/:username", get(|State(state): State<AppState>, headers: axum::http::HeaderMap, axum::extract::Path(username): axum::extract::Path<String>| async move {
+            get_pending_messages(State(state), headers, username).await
         }))
+        .route("/ws", get(ws_handler))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -65,6 +90,11 @@ async fn main() {
         .expect("Server failed to start");
 }
 
+/// Upgrade an HTTP connection to a realtime chat WebSocket
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_websocket(socket, state))
+}
+
 /// Health check endpoint
 async fn health_check() -> impl IntoResponse {
     Json(serde_json::json!({
@@ -1,10 +1,26 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::accounts::{AccountStore, DEFAULT_ACCOUNTS_DB_URL};
+use crate::connection_manager::ConnectionManager;
+
+/// How long a user can go without a heartbeat before the presence sweeper
+/// evicts them.
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the presence sweeper scans for stale users.
+const PRESENCE_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+/// Maximum number of encrypted messages held per user while they're offline;
+/// once full, the oldest queued message is dropped to make room.
+pub(crate) const MAILBOX_CAPACITY: usize = 100;
+/// Maximum usernames `SearchUsers`/`search-users` can return in one call, so
+/// a caller can't enumerate the whole directory through a high `limit`.
+pub(crate) const SEARCH_RESULTS_LIMIT: usize = 50;
 
 /// User data stored in memory (ephemeral)
 /// Note: Not serializable because Instant cannot be serialized
@@ -16,8 +32,23 @@ pub struct UserData {
     #[allow(dead_code)] // Stored for routing and future features
     pub username: String,     // Username (must be unique)
     pub public_key: String,   // Base64 encoded public key
-    #[allow(dead_code)] // Kept for future features (connection time tracking, etc.)
     pub last_seen: Instant,
+    /// Opaque bearer token handed out at registration; callers must present
+    /// it to act as this user on the signaling endpoints
+    pub session_token: String,
+}
+
+/// A single WebRTC signaling packet, tagged by kind so recipients can match
+/// exhaustively instead of comparing strings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SignalingPacket {
+    #[serde(rename = "offer")]
+    Offer { sdp: String },
+    #[serde(rename = "answer")]
+    Answer { sdp: String },
+    #[serde(rename = "ice-candidate")]
+    IceCandidate { candidate: String },
 }
 
 /// Pending signaling message
@@ -25,8 +56,7 @@ pub struct UserData {
 pub struct PendingSignalingMessage {
     pub from: String,
     pub to: String,
-    pub message_type: String, // "offer", "answer", "ice-candidate"
-    pub data: String, // SDP or ICE candidate JSON
+    pub packet: SignalingPacket,
 }
 
 /// Application state - all data stored in memory (ephemeral)
@@ -38,15 +68,97 @@ pub struct AppState {
     pub signaling: Arc<RwLock<HashMap<String, SignalingData>>>,
     /// Pending signaling messages: username -> Vec<PendingSignalingMessage>
     pub pending_messages: Arc<RwLock<HashMap<String, Vec<PendingSignalingMessage>>>>,
+    /// Live WebSocket connections, used to push signaling messages instead of polling
+    pub connection_manager: ConnectionManager,
+    /// Metadata about currently open WebSocket connections, keyed by username
+    pub connections: Arc<RwLock<HashMap<String, ConnectionInfo>>>,
+    /// Offline mailbox: encrypted messages waiting for a recipient who isn't
+    /// connected yet, oldest-dropped once `MAILBOX_CAPACITY` is reached
+    pub mailboxes: Arc<RwLock<HashMap<String, VecDeque<MailboxMessage>>>>,
+    /// Persistent, password-protected username directory, so a name claimed
+    /// with `CreateAccount` can't be reclaimed by someone else after a
+    /// restart or disconnect
+    pub accounts: AccountStore,
+    /// Online usernames, keyed by lowercase form (sorted, for prefix range
+    /// scans) mapping to the canonical-case username actually used to key
+    /// `users`/`connections`/rooms, kept in sync on register/disconnect so
+    /// `SearchUsers` can prefix-match without walking the whole roster while
+    /// still returning names callers can route to
+    pub usernames: Arc<RwLock<BTreeMap<String, String>>>,
+    /// Group chat rooms, keyed by room name
+    pub rooms: Arc<RwLock<HashMap<String, Room>>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
-        Self {
+        let state = Self {
             users: Arc::new(RwLock::new(HashMap::new())),
             signaling: Arc::new(RwLock::new(HashMap::new())),
             pending_messages: Arc::new(RwLock::new(HashMap::new())),
+            connection_manager: ConnectionManager::new(),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            mailboxes: Arc::new(RwLock::new(HashMap::new())),
+            accounts: AccountStore::connect_lazy(DEFAULT_ACCOUNTS_DB_URL)
+                .expect("Failed to open accounts database"),
+            usernames: Arc::new(RwLock::new(BTreeMap::new())),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        state.spawn_presence_sweeper();
+
+        state
+    }
+
+    /// Background task that evicts users who haven't sent a heartbeat within
+    /// `PRESENCE_TIMEOUT`, keeping `users`, `signaling`, `pending_messages`,
+    /// `usernames`, `rooms` and the `ConnectionManager` honest for peers that
+    /// dropped without calling `disconnect_user`.
+    fn spawn_presence_sweeper(&self) {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(PRESENCE_SWEEP_INTERVAL).await;
+
+                let stale: Vec<String> = {
+                    let users = state.users.read().await;
+                    users
+                        .values()
+                        .filter(|user| user.last_seen.elapsed() > PRESENCE_TIMEOUT)
+                        .map(|user| user.username.clone())
+                        .collect()
+                };
+
+                for username in &stale {
+                    state.connection_manager.remove_all(username).await;
+                    state.forget_user(username).await;
+                    info!("Evicted stale user (no heartbeat): {}", username);
+                }
+            }
+        });
+    }
+
+    /// Clear every trace of `username` from shared state once their last
+    /// connection is gone, whether that's because the connection closed
+    /// normally (`ConnectionGuard::Drop`) or because the presence sweeper
+    /// evicted them for going quiet. Reconciles them out of any rooms they
+    /// were still in and rebroadcasts the online roster, same as a clean
+    /// disconnect would. Doesn't touch `connection_manager` - a single
+    /// device disconnecting and a sweeper eviction remove different things
+    /// there, so callers handle that themselves before calling this.
+    pub(crate) async fn forget_user(&self, username: &str) {
+        self.users.write().await.remove(username);
+        self.signaling.write().await.remove(username);
+        self.pending_messages.write().await.remove(username);
+        self.connections.write().await.remove(username);
+        self.usernames.write().await.remove(&username.to_lowercase());
+
+        let departed_rooms = crate::rooms::leave_all_rooms(self, username).await;
+        for (room, members) in &departed_rooms {
+            crate::rooms::broadcast_room_members(self, room, members).await;
         }
+
+        crate::websocket::broadcast_user_list(self).await;
     }
 }
 
@@ -90,6 +202,9 @@ pub struct RegisterRequest {
 pub struct RegisterResponse {
     pub user_id: String,
     pub username: String,
+    /// Bearer token to send as `Authorization: Bearer <token>` on subsequent
+    /// signaling requests made as this user
+    pub session_token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -97,6 +212,12 @@ pub struct CheckUsernameRequest {
     pub username: String,
 }
 
+/// Heartbeat ping used to keep a user's presence alive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeartbeatRequest {
+    pub username: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CheckUsernameResponse {
     pub available: bool,
@@ -135,6 +256,19 @@ pub struct OnlineUsersResponse {
     pub users: Vec<String>,
 }
 
+/// Query for `GET /api/search-users`; `limit` is optional and capped at
+/// `SEARCH_RESULTS_LIMIT` server-side
+#[derive(Debug, Deserialize)]
+pub struct SearchUsersQuery {
+    pub prefix: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchUsersResponse {
+    pub users: Vec<String>,
+}
+
 /// Encrypted message payload (server never decrypts this)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedMessage {
@@ -143,3 +277,103 @@ pub struct EncryptedMessage {
     pub public_key: String,  // Sender's public key (Base64 encoded)
 }
 
+/// Request to relay an encrypted message to another user, live if they're
+/// connected or via their offline mailbox otherwise
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendEncryptedMessageRequest {
+    pub from: String,
+    pub to: String,
+    pub encrypted: EncryptedMessage,
+}
+
+/// An encrypted message held in a user's offline mailbox until they reconnect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxMessage {
+    pub id: String,
+    pub from: String,
+    pub encrypted: EncryptedMessage,
+    pub timestamp: u64,
+}
+
+/// A member's permission level within a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rank {
+    Owner,
+    Admin,
+    Member,
+}
+
+/// A group chat room. Like 1:1 messages, room messages carry only an opaque
+/// `EncryptedMessage` - the server just tracks membership and ranks, it
+/// never sees plaintext or even who can decrypt what.
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub members: HashMap<String, Rank>,
+}
+
+/// Metadata about an open WebSocket connection
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    #[allow(dead_code)] // Stored for routing and future features
+    pub username: String,
+    #[allow(dead_code)] // Kept for future features (connection time tracking, etc.)
+    pub connected_at: Instant,
+}
+
+/// Messages exchanged over the realtime WebSocket chat connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WebSocketMessage {
+    /// Client asks whether a username is free before registering
+    CheckUsername { username: String },
+    /// Server's answer to `CheckUsername`
+    UsernameAvailable { available: bool, message: String },
+    /// Client creates a new persistent account, hashing `password` with
+    /// Argon2id before it's stored
+    CreateAccount { username: String, password: String, public_key: String },
+    /// Client logs in to an existing account for this connection
+    Register { username: String, password: String, public_key: String },
+    /// Server confirms a successful `Register`
+    Registered { user_id: String, username: String },
+    /// Server pushes the current online roster
+    OnlineUsers { users: Vec<String> },
+    /// Client searches for online usernames starting with `prefix`
+    SearchUsers { prefix: String, limit: usize },
+    /// Server's answer to `SearchUsers`, capped at `SEARCH_RESULTS_LIMIT`
+    SearchResults { users: Vec<String> },
+    /// Client sends an encrypted message to another user
+    SendMessage { to: String, encrypted: EncryptedMessage },
+    /// Server delivers an encrypted message from another user
+    Message {
+        id: String,
+        from: String,
+        to: String,
+        encrypted: EncryptedMessage,
+        timestamp: u64,
+    },
+    /// Client creates a new room, becoming its Owner
+    CreateRoom { room: String },
+    /// Client joins an existing room as a Member
+    JoinRoom { room: String },
+    /// Client leaves a room it's a member of
+    LeaveRoom { room: String },
+    /// Client (Owner/Admin) removes `target` from `room`
+    KickMember { room: String, target: String },
+    /// Client (Owner/Admin) deletes `room` outright
+    DeleteRoom { room: String },
+    /// Client sends an encrypted payload to every other member of a room
+    SendRoomMessage { room: String, encrypted: EncryptedMessage },
+    /// Server delivers a room message from another member
+    RoomMessage {
+        room: String,
+        from: String,
+        encrypted: EncryptedMessage,
+        timestamp: u64,
+    },
+    /// Server pushes a room's current membership after a join/leave/kick/
+    /// delete, so clients can re-run their group key agreement
+    RoomMembers { room: String, members: Vec<String> },
+    /// Server reports a problem with the last request
+    Error { message: String },
+}
+
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use crate::models::{AppState, EncryptedMessage, Rank, Room, WebSocketMessage};
+use crate::transport::Frame;
+
+/// Outcome of a room operation; the `Err` string is serialized straight
+/// back to the caller as a `WebSocketMessage::Error`
+pub type RoomResult<T> = Result<T, String>;
+
+/// Create `room` with `creator` as its sole member and Owner
+pub async fn create_room(state: &AppState, room: &str, creator: &str) -> RoomResult<()> {
+    let mut rooms = state.rooms.write().await;
+    if rooms.contains_key(room) {
+        return Err("Room already exists".to_string());
+    }
+
+    let mut members = HashMap::new();
+    members.insert(creator.to_string(), Rank::Owner);
+    rooms.insert(room.to_string(), Room { members });
+
+    Ok(())
+}
+
+/// Add `member` to `room` as a plain Member, returning the resulting roster
+pub async fn join_room(state: &AppState, room: &str, member: &str) -> RoomResult<Vec<String>> {
+    let mut rooms = state.rooms.write().await;
+    let Some(room_data) = rooms.get_mut(room) else {
+        return Err("Room not found".to_string());
+    };
+
+    room_data.members.entry(member.to_string()).or_insert(Rank::Member);
+    Ok(room_data.members.keys().cloned().collect())
+}
+
+/// Remove `member` from `room`, deleting the room once it has no members
+/// left, returning the resulting roster (empty if the room was deleted)
+pub async fn leave_room(state: &AppState, room: &str, member: &str) -> RoomResult<Vec<String>> {
+    let mut rooms = state.rooms.write().await;
+    let Some(room_data) = rooms.get_mut(room) else {
+        return Err("Room not found".to_string());
+    };
+
+    room_data.members.remove(member);
+    let remaining: Vec<String> = room_data.members.keys().cloned().collect();
+
+    if remaining.is_empty() {
+        rooms.remove(room);
+    }
+
+    Ok(remaining)
+}
+
+/// Remove `target` from `room`; only an Owner or Admin may kick another
+/// member
+pub async fn kick_member(
+    state: &AppState,
+    room: &str,
+    kicker: &str,
+    target: &str,
+) -> RoomResult<Vec<String>> {
+    let mut rooms = state.rooms.write().await;
+    let Some(room_data) = rooms.get_mut(room) else {
+        return Err("Room not found".to_string());
+    };
+
+    match room_data.members.get(kicker) {
+        Some(Rank::Owner) | Some(Rank::Admin) => {}
+        _ => return Err("Only an owner or admin can remove members".to_string()),
+    }
+
+    room_data.members.remove(target);
+    Ok(room_data.members.keys().cloned().collect())
+}
+
+/// Remove `member` from every room they currently belong to, deleting any
+/// room left with no members behind. Used on disconnect, where (unlike
+/// `leave_room`) there's no single room the caller is targeting - the
+/// membership reconciliation has to sweep all of them. Returns each changed
+/// room together with its remaining roster, so the caller can broadcast
+/// `RoomMembers` the same way `leave_room` does.
+pub async fn leave_all_rooms(state: &AppState, member: &str) -> Vec<(String, Vec<String>)> {
+    let mut rooms = state.rooms.write().await;
+
+    let affected: Vec<String> = rooms
+        .iter()
+        .filter(|(_, room)| room.members.contains_key(member))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let mut changed = Vec::with_capacity(affected.len());
+    for room_name in affected {
+        let remaining = {
+            let room_data = rooms.get_mut(&room_name).expect("just collected from this map");
+            room_data.members.remove(member);
+            room_data.members.keys().cloned().collect::<Vec<String>>()
+        };
+
+        if remaining.is_empty() {
+            rooms.remove(&room_name);
+        }
+
+        changed.push((room_name, remaining));
+    }
+
+    changed
+}
+
+/// Delete `room` outright; only its Owner or an Admin may do this. Returns
+/// the roster at the time of deletion so callers can notify former members.
+pub async fn delete_room(state: &AppState, room: &str, requester: &str) -> RoomResult<Vec<String>> {
+    let mut rooms = state.rooms.write().await;
+    let Some(room_data) = rooms.get(room) else {
+        return Err("Room not found".to_string());
+    };
+
+    match room_data.members.get(requester) {
+        Some(Rank::Owner) | Some(Rank::Admin) => {}
+        _ => return Err("Only an owner or admin can delete this room".to_string()),
+    }
+
+    let members: Vec<String> = room_data.members.keys().cloned().collect();
+    rooms.remove(room);
+
+    Ok(members)
+}
+
+/// Fan an encrypted room payload out to every other member, skipping
+/// `sender`. Since the server stays zero-knowledge, `encrypted` carries
+/// either per-recipient ciphertext or a shared sender-key blob chosen by
+/// the client - the server never inspects it.
+pub async fn send_room_message(
+    state: &AppState,
+    room: &str,
+    sender: &str,
+    encrypted: EncryptedMessage,
+    timestamp: u64,
+) -> RoomResult<()> {
+    let rooms = state.rooms.read().await;
+    let Some(room_data) = rooms.get(room) else {
+        return Err("Room not found".to_string());
+    };
+
+    if !room_data.members.contains_key(sender) {
+        return Err("Not a member of this room".to_string());
+    }
+
+    let recipients: Vec<String> = room_data
+        .members
+        .keys()
+        .filter(|member| member.as_str() != sender)
+        .cloned()
+        .collect();
+    drop(rooms);
+
+    let message = WebSocketMessage::RoomMessage {
+        room: room.to_string(),
+        from: sender.to_string(),
+        encrypted,
+        timestamp,
+    };
+
+    let payload =
+        serde_json::to_string(&message).map_err(|e| format!("Failed to serialize message: {}", e))?;
+
+    for member in recipients {
+        let _ = state
+            .connection_manager
+            .send_to_user(&member, Frame::Text(payload.clone()))
+            .await;
+    }
+
+    Ok(())
+}
+
+/// Broadcast a room's current membership to every member still in it, so
+/// clients can re-run their group key agreement
+pub async fn broadcast_room_members(state: &AppState, room: &str, members: &[String]) {
+    notify_room_members(state, room, members, members).await;
+}
+
+/// Tell every former member a room was deleted, so clients can drop their
+/// local room state
+pub async fn notify_room_deleted(state: &AppState, room: &str, former_members: &[String]) {
+    notify_room_members(state, room, former_members, &[]).await;
+}
+
+/// Tell a kicked member they were removed from `room`, so their client drops
+/// its local room state the same way it would after `notify_room_deleted`
+pub async fn notify_member_kicked(state: &AppState, room: &str, target: &str) {
+    notify_room_members(state, room, std::slice::from_ref(&target.to_string()), &[]).await;
+}
+
+/// Send a room's membership (`members`) to each of `recipients`
+async fn notify_room_members(state: &AppState, room: &str, recipients: &[String], members: &[String]) {
+    let message = WebSocketMessage::RoomMembers {
+        room: room.to_string(),
+        members: members.to_vec(),
+    };
+
+    let Ok(payload) = serde_json::to_string(&message) else {
+        return;
+    };
+
+    for member in recipients {
+        let _ = state
+            .connection_manager
+            .send_to_user(member, Frame::Text(payload.clone()))
+            .await;
+    }
+}
@@ -1,12 +1,15 @@
-use crate::models::{AppState, PendingSignalingMessage};
-use axum::{extract::State, http::StatusCode, Json};
+use crate::models::{AppState, MailboxMessage, PendingSignalingMessage, SignalingPacket};
+use crate::transport::Frame;
+use axum::{extract::Query, extract::State, http::HeaderMap, http::StatusCode, Json};
 use std::time::Instant;
 use tracing::info;
 use uuid::Uuid;
 
 use crate::models::{
-    AnswerRequest, CheckUsernameRequest, CheckUsernameResponse, IceCandidateRequest,
-    OfferRequest, OnlineUsersResponse, RegisterRequest, RegisterResponse, SignalingResponse,
+    AnswerRequest, CheckUsernameRequest, CheckUsernameResponse, HeartbeatRequest,
+    IceCandidateRequest, OfferRequest, OnlineUsersResponse, RegisterRequest, RegisterResponse,
+    SearchUsersQuery, SearchUsersResponse, SendEncryptedMessageRequest, SignalingResponse,
+    SEARCH_RESULTS_LIMIT,
 };
 
 /// Register a new user for WebRTC signaling
@@ -29,6 +32,7 @@ pub async fn register_user(
     }
 
     let user_id = Uuid::new_v4().to_string();
+    let session_token = Uuid::new_v4().to_string();
 
     // Store user data
     users.insert(
@@ -38,6 +42,7 @@ pub async fn register_user(
             username: request.username.clone(),
             public_key: request.public_key.clone(),
             last_seen: Instant::now(),
+            session_token: session_token.clone(),
         },
     );
 
@@ -51,11 +56,18 @@ pub async fn register_user(
         },
     );
 
+    state
+        .usernames
+        .write()
+        .await
+        .insert(request.username.to_lowercase(), request.username.clone());
+
     info!("User registered: {}", request.username);
 
     Ok(Json(RegisterResponse {
         user_id,
         username: request.username,
+        session_token,
     }))
 }
 
@@ -86,14 +98,119 @@ pub async fn get_online_users(
     Json(OnlineUsersResponse { users: usernames })
 }
 
+/// Case-insensitive prefix search over online usernames, served from the
+/// sorted `usernames` index via a range scan rather than a full iteration.
+/// The index is keyed by lowercase name but maps to the canonical-case
+/// username, so results are returned in the casing that actually keys
+/// `users`/`connections`/rooms and can be used directly for routing.
+/// `limit` is capped at `SEARCH_RESULTS_LIMIT` so a caller can't enumerate
+/// the whole directory in one request.
+pub(crate) async fn search_usernames(state: &AppState, prefix: &str, limit: usize) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    let limit = limit.min(SEARCH_RESULTS_LIMIT);
+
+    let usernames = state.usernames.read().await;
+    usernames
+        .range(prefix.clone()..)
+        .take_while(|(lower, _)| lower.starts_with(&prefix))
+        .take(limit)
+        .map(|(_, canonical)| canonical.clone())
+        .collect()
+}
+
+/// Prefix-search online usernames over HTTP
+pub async fn search_users(
+    State(state): State<AppState>,
+    Query(query): Query<SearchUsersQuery>,
+) -> Json<SearchUsersResponse> {
+    let limit = query.limit.unwrap_or(SEARCH_RESULTS_LIMIT);
+    let users = search_usernames(&state, &query.prefix, limit).await;
+    Json(SearchUsersResponse { users })
+}
+
+/// Refresh a user's presence so the background sweeper doesn't evict them
+pub async fn heartbeat(
+    State(state): State<AppState>,
+    Json(request): Json<HeartbeatRequest>,
+) -> Result<Json<SignalingResponse>, (StatusCode, Json<SignalingResponse>)> {
+    let mut users = state.users.write().await;
+    let Some(user) = users.get_mut(&request.username) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(SignalingResponse {
+                success: false,
+                message: "User not registered".to_string(),
+            }),
+        ));
+    };
+    user.last_seen = Instant::now();
+    drop(users);
+
+    if let Some(signaling_data) = state.signaling.write().await.get_mut(&request.username) {
+        signaling_data.last_seen = Instant::now();
+    }
+
+    Ok(Json(SignalingResponse {
+        success: true,
+        message: "Heartbeat received".to_string(),
+    }))
+}
+
+/// Extract the bearer token from an `Authorization: Bearer <token>` header
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+}
+
+/// Verify the caller's bearer token matches the session token issued to
+/// `username` at registration, rejecting with 401 on a spoofed `from`/
+/// `username` field. Comparison is constant-time to avoid leaking the token
+/// through response-timing side channels.
+async fn authorize(
+    state: &AppState,
+    username: &str,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<SignalingResponse>)> {
+    let unauthorized = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(SignalingResponse {
+                success: false,
+                message: "Invalid or missing session token".to_string(),
+            }),
+        )
+    };
+
+    let Some(token) = bearer_token(headers) else {
+        return Err(unauthorized());
+    };
+
+    let users = state.users.read().await;
+    let Some(user) = users.get(username) else {
+        return Err(unauthorized());
+    };
+
+    if sodiumoxide::utils::memcmp(token.as_bytes(), user.session_token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(unauthorized())
+    }
+}
+
 /// Handle WebRTC offer (initiate connection)
 pub async fn handle_offer(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<OfferRequest>,
 ) -> Result<Json<SignalingResponse>, (StatusCode, Json<SignalingResponse>)> {
+    authorize(&state, &request.from, &headers).await?;
+
     // Verify both users are online
     let users = state.users.read().await;
-    
+
     if !users.contains_key(&request.from) {
         return Err((
             StatusCode::UNAUTHORIZED,
@@ -115,16 +232,16 @@ pub async fn handle_offer(
     }
     drop(users);
 
-    // Store offer for recipient to poll
-    let mut pending = state.pending_messages.write().await;
-    let recipient_messages = pending.entry(request.to.clone()).or_insert_with(Vec::new);
-    recipient_messages.push(PendingSignalingMessage {
-        from: request.from.clone(),
-        to: request.to.clone(),
-        message_type: "offer".to_string(),
-        data: request.offer,
-    });
-    
+    deliver_or_queue(
+        &state,
+        PendingSignalingMessage {
+            from: request.from.clone(),
+            to: request.to.clone(),
+            packet: SignalingPacket::Offer { sdp: request.offer },
+        },
+    )
+    .await;
+
     Ok(Json(SignalingResponse {
         success: true,
         message: "Offer received".to_string(),
@@ -134,8 +251,11 @@ pub async fn handle_offer(
 /// Handle WebRTC answer
 pub async fn handle_answer(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<AnswerRequest>,
 ) -> Result<Json<SignalingResponse>, (StatusCode, Json<SignalingResponse>)> {
+    authorize(&state, &request.from, &headers).await?;
+
     let users = state.users.read().await;
     
     if !users.contains_key(&request.from) || !users.contains_key(&request.to) {
@@ -149,15 +269,15 @@ pub async fn handle_answer(
     }
     drop(users);
 
-    // Store answer for recipient to poll
-    let mut pending = state.pending_messages.write().await;
-    let recipient_messages = pending.entry(request.to.clone()).or_insert_with(Vec::new);
-    recipient_messages.push(PendingSignalingMessage {
-        from: request.from.clone(),
-        to: request.to.clone(),
-        message_type: "answer".to_string(),
-        data: request.answer,
-    });
+    deliver_or_queue(
+        &state,
+        PendingSignalingMessage {
+            from: request.from.clone(),
+            to: request.to.clone(),
+            packet: SignalingPacket::Answer { sdp: request.answer },
+        },
+    )
+    .await;
 
     Ok(Json(SignalingResponse {
         success: true,
@@ -168,8 +288,11 @@ pub async fn handle_answer(
 /// Handle ICE candidate exchange
 pub async fn handle_ice_candidate(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(request): Json<IceCandidateRequest>,
 ) -> Result<Json<SignalingResponse>, (StatusCode, Json<SignalingResponse>)> {
+    authorize(&state, &request.from, &headers).await?;
+
     let users = state.users.read().await;
     
     if !users.contains_key(&request.from) || !users.contains_key(&request.to) {
@@ -183,15 +306,17 @@ pub async fn handle_ice_candidate(
     }
     drop(users);
 
-    // Store ICE candidate for recipient to poll
-    let mut pending = state.pending_messages.write().await;
-    let recipient_messages = pending.entry(request.to.clone()).or_insert_with(Vec::new);
-    recipient_messages.push(PendingSignalingMessage {
-        from: request.from.clone(),
-        to: request.to.clone(),
-        message_type: "ice-candidate".to_string(),
-        data: request.candidate,
-    });
+    deliver_or_queue(
+        &state,
+        PendingSignalingMessage {
+            from: request.from.clone(),
+            to: request.to.clone(),
+            packet: SignalingPacket::IceCandidate {
+                candidate: request.candidate,
+            },
+        },
+    )
+    .await;
 
     Ok(Json(SignalingResponse {
         success: true,
@@ -199,14 +324,122 @@ pub async fn handle_ice_candidate(
     }))
 }
 
+/// Relay an end-to-end encrypted chat message to another user. The server
+/// never inspects `encrypted` - it's delivered live if the recipient has a
+/// WebSocket or WebTransport connection open, or held in their offline
+/// mailbox otherwise.
+pub async fn relay_message(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<SendEncryptedMessageRequest>,
+) -> Result<Json<SignalingResponse>, (StatusCode, Json<SignalingResponse>)> {
+    authorize(&state, &request.from, &headers).await?;
+
+    // A recipient is "known" if they're online right now, or if they have a
+    // persistent account to deliver into later - checking only `state.users`
+    // (who's online) would reject every genuinely offline recipient before
+    // the mailbox fallback below ever runs, defeating the whole point of
+    // store-and-forward delivery.
+    let recipient_known = state.users.read().await.contains_key(&request.to)
+        || state
+            .accounts
+            .account_exists(&request.to)
+            .await
+            .unwrap_or(false);
+
+    if !recipient_known {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(SignalingResponse {
+                success: false,
+                message: "Recipient not found".to_string(),
+            }),
+        ));
+    }
+
+    let message = MailboxMessage {
+        id: Uuid::new_v4().to_string(),
+        from: request.from,
+        encrypted: request.encrypted,
+        timestamp: chrono::Utc::now().timestamp_millis() as u64,
+    };
+
+    let ws_message = crate::models::WebSocketMessage::Message {
+        id: message.id.clone(),
+        from: message.from.clone(),
+        to: request.to.clone(),
+        encrypted: message.encrypted.clone(),
+        timestamp: message.timestamp,
+    };
+
+    let delivered = match serde_json::to_string(&ws_message) {
+        Ok(payload) => state
+            .connection_manager
+            .send_to_user(&request.to, Frame::Text(payload))
+            .await
+            .is_ok(),
+        Err(_) => false,
+    };
+
+    if !delivered {
+        let mut mailboxes = state.mailboxes.write().await;
+        let mailbox = mailboxes.entry(request.to).or_insert_with(Default::default);
+        if mailbox.len() >= crate::models::MAILBOX_CAPACITY {
+            mailbox.pop_front();
+        }
+        mailbox.push_back(message);
+    }
+
+    Ok(Json(SignalingResponse {
+        success: true,
+        message: "Message queued for delivery".to_string(),
+    }))
+}
+
+/// Deliver a signaling message to a live WebSocket or WebTransport connection,
+/// or queue it for
+/// polling if the recipient isn't connected. To keep offer/answer/ICE ordering
+/// intact per recipient, anything already queued takes priority over a fresh
+/// live push, so a candidate can't race ahead of an offer still waiting to be
+/// picked up.
+async fn deliver_or_queue(state: &AppState, message: PendingSignalingMessage) {
+    let mut pending = state.pending_messages.write().await;
+    let queue = pending.entry(message.to.clone()).or_insert_with(Vec::new);
+
+    if !queue.is_empty() {
+        queue.push(message);
+        return;
+    }
+
+    let payload = match serde_json::to_string(&message) {
+        Ok(payload) => payload,
+        Err(_) => {
+            queue.push(message);
+            return;
+        }
+    };
+
+    match state
+        .connection_manager
+        .send_to_user(&message.to, Frame::Text(payload))
+        .await
+    {
+        Ok(()) => {}
+        Err(_) => queue.push(message),
+    }
+}
+
 /// Get pending signaling messages for a user
 pub async fn get_pending_messages(
     State(state): State<AppState>,
+    headers: HeaderMap,
     username: String,
-) -> Json<Vec<PendingSignalingMessage>> {
+) -> Result<Json<Vec<PendingSignalingMessage>>, (StatusCode, Json<SignalingResponse>)> {
+    authorize(&state, &username, &headers).await?;
+
     let mut pending = state.pending_messages.write().await;
     let messages = pending.remove(&username).unwrap_or_default();
-    Json(messages)
+    Ok(Json(messages))
 }
 
 /// Remove user on disconnect (for future use)
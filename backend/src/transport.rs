@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+
+/// A single frame exchanged over a signaling transport, independent of
+/// whether it arrived over a WebSocket or a WebTransport connection
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping,
+    Pong,
+    Close,
+}
+
+/// Receiving half of a signaling transport
+#[async_trait]
+pub trait Transport: Send {
+    async fn recv(&mut self) -> Option<Result<Frame, String>>;
+}
+
+/// Sending half of a signaling transport
+#[async_trait]
+pub trait TransportSender: Send {
+    async fn send(&mut self, frame: Frame) -> Result<(), String>;
+}
+
+impl From<Message> for Frame {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Text(text) => Frame::Text(text),
+            Message::Binary(data) => Frame::Binary(data),
+            Message::Ping(_) => Frame::Ping,
+            Message::Pong(_) => Frame::Pong,
+            Message::Close(_) => Frame::Close,
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(data) => Message::Binary(data),
+            Frame::Ping => Message::Ping(Vec::new()),
+            Frame::Pong => Message::Pong(Vec::new()),
+            Frame::Close => Message::Close(None),
+        }
+    }
+}
+
+/// `Transport` over the receiving half of a split `axum` WebSocket
+pub struct WebSocketTransport {
+    receiver: SplitStream<WebSocket>,
+}
+
+impl WebSocketTransport {
+    pub fn new(receiver: SplitStream<WebSocket>) -> Self {
+        Self { receiver }
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn recv(&mut self) -> Option<Result<Frame, String>> {
+        match self.receiver.next().await {
+            Some(Ok(message)) => Some(Ok(Frame::from(message))),
+            Some(Err(e)) => Some(Err(e.to_string())),
+            None => None,
+        }
+    }
+}
+
+/// `TransportSender` over the sending half of a split `axum` WebSocket
+pub struct WebSocketSender {
+    sender: SplitSink<WebSocket, Message>,
+}
+
+impl WebSocketSender {
+    pub fn new(sender: SplitSink<WebSocket, Message>) -> Self {
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl TransportSender for WebSocketSender {
+    async fn send(&mut self, frame: Frame) -> Result<(), String> {
+        self.sender
+            .send(Message::from(frame))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
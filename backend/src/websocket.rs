@@ -1,37 +1,103 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures_util::{SinkExt, StreamExt};
+use axum::extract::ws::WebSocket;
+use futures_util::StreamExt;
 use serde_json;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
+use crate::connection_manager::ConnectionGuard;
 use crate::models::{AppState, ConnectionInfo, UserData, WebSocketMessage};
+use crate::transport::{Frame, Transport, TransportSender, WebSocketSender, WebSocketTransport};
 
-/// Handle WebSocket connection
+/// How often the server pings an idle connection
+const SOCKET_PING_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a connection can go without any traffic before it's reaped
+const SOCKET_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2 * 20);
+
+/// Handle a WebSocket upgrade by splitting it into the transport-agnostic
+/// [`Transport`]/[`TransportSender`] halves and running the shared
+/// connection loop
 pub async fn handle_websocket(socket: WebSocket, state: AppState) {
-    let (mut sender, mut receiver) = socket.split();
-    
-    // Create channel for sending messages to this connection
-    let (tx, mut rx) = mpsc::unbounded_channel();
-    
+    let (sender, receiver) = socket.split();
+    handle_connection(WebSocketTransport::new(receiver), WebSocketSender::new(sender), state).await;
+}
+
+/// Run the signaling connection loop over any transport: registration,
+/// message forwarding, and the ping/idle-timeout heartbeat are all
+/// transport-agnostic, so this same loop drives both WebSocket and
+/// WebTransport connections.
+pub async fn handle_connection<T, S>(mut transport: T, mut sender: S, state: AppState)
+where
+    T: Transport,
+    S: TransportSender + Send + 'static,
+{
+    // Create channel for sending frames to this connection
+    let (tx, mut rx) = mpsc::unbounded_channel::<Frame>();
+
     // Clone tx for the connection manager (we'll register it after user registers)
     let tx_for_manager = tx.clone();
-    
-    // Spawn task to forward messages from channel to WebSocket
+
+    // Spawn task to forward frames from channel to the transport
     let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(msg).await.is_err() {
+        while let Some(frame) = rx.recv().await {
+            if sender.send(frame).await.is_err() {
                 break;
             }
         }
     });
-    
+
+    // Tracks the last time any frame (including a Pong reply) was seen on
+    // this connection, so the ticker below can reap it if it goes quiet for
+    // longer than `SOCKET_HEARTBEAT_TIMEOUT`.
+    let mut last_seen = Instant::now();
+    let mut ping_ticker = tokio::time::interval(SOCKET_PING_INTERVAL);
+
     let mut username: Option<String> = None;
+    // Created once the connection registers a username; its Drop impl
+    // guarantees cleanup even if this task returns early or panics.
+    let mut guard: Option<ConnectionGuard> = None;
+
+    // Handle incoming frames, racing each one against the ping ticker so an
+    // idle connection still gets reaped even if the client never sends
+    // another frame.
+    'connection: loop {
+        let msg = tokio::select! {
+            msg = transport.recv() => match msg {
+                Some(msg) => msg,
+                None => break 'connection,
+            },
+            _ = ping_ticker.tick() => {
+                if last_seen.elapsed() > SOCKET_HEARTBEAT_TIMEOUT {
+                    warn!("Connection timed out (no heartbeat)");
+                    break 'connection;
+                }
+                if tx.send(Frame::Ping).is_err() {
+                    break 'connection;
+                }
+                continue 'connection;
+            }
+        };
+
+        if msg.is_ok() {
+            last_seen = Instant::now();
+
+            // Keep `UserData.last_seen` (consulted by the presence sweeper)
+            // in sync with the connection's own traffic, not just the HTTP
+            // `/api/heartbeat` path: a client that only ever talks over this
+            // socket would otherwise get reaped out from under a live
+            // connection.
+            if let Some(ref user) = username {
+                if let Some(user_data) = state.users.write().await.get_mut(user) {
+                    user_data.last_seen = Instant::now();
+                }
+            }
+        }
 
-    // Handle incoming messages
-    while let Some(msg) = receiver.next().await {
         match msg {
-            Ok(Message::Text(text)) => {
+            Ok(Frame::Pong) | Ok(Frame::Ping) => {
+                // Traffic timestamp already refreshed above; nothing else to do.
+            }
+            Ok(Frame::Text(text)) => {
                 match serde_json::from_str::<WebSocketMessage>(&text) {
                     Ok(ws_msg) => {
                         match ws_msg {
@@ -50,90 +116,68 @@ pub async fn handle_websocket(socket: WebSocket, state: AppState) {
                                     },
                                 };
                                 
-                                if tx.send(Message::Text(serde_json::to_string(&response).unwrap())).is_err() {
+                                if tx.send(Frame::Text(serde_json::to_string(&response).unwrap())).is_err() {
                                     error!("Failed to send username check response");
                                     break;
                                 }
                             }
-                            WebSocketMessage::Register { username: user, public_key } => {
-                                // Check if username already exists
-                                let mut users = state.users.write().await;
-                                
-                                // Check if username is already taken
-                                let username_taken = users.values().any(|u| u.username == user);
-                                
-                                if username_taken {
-                                    let error_msg = WebSocketMessage::Error {
-                                        message: "Username already exists. Please choose a different username.".to_string(),
-                                    };
-                                    drop(users);
-                                    if tx.send(Message::Text(serde_json::to_string(&error_msg).unwrap())).is_err() {
-                                        error!("Failed to send error");
+                            WebSocketMessage::CreateAccount { username: user, password, public_key } => {
+                                match state.accounts.create_account(&user, &password, &public_key).await {
+                                    Ok(()) => {
+                                        if complete_registration(
+                                            &state,
+                                            user,
+                                            public_key,
+                                            &tx,
+                                            tx_for_manager.clone(),
+                                            &mut username,
+                                            &mut guard,
+                                        )
+                                        .await
+                                        {
+                                            break;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let error_msg = WebSocketMessage::Error { message: e.to_string() };
+                                        if tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap())).is_err() {
+                                            break;
+                                        }
                                     }
-                                    continue;
-                                }
-                                
-                                // Generate unique user ID
-                                let user_id = uuid::Uuid::new_v4().to_string();
-                                
-                                // Register user
-                                username = Some(user.clone());
-                                
-                                // Store user data keyed by username (for routing)
-                                users.insert(
-                                    user.clone(),  // Use username as key for routing
-                                    UserData {
-                                        user_id: user_id.clone(),
-                                        username: user.clone(),
-                                        public_key: public_key.clone(),
-                                        last_seen: Instant::now(),
-                                    },
-                                );
-                                drop(users);
-                                
-                                // Send registration success
-                                let registered_msg = WebSocketMessage::Registered {
-                                    user_id: user_id.clone(),
-                                    username: user.clone(),
-                                };
-                                
-                                if tx.send(Message::Text(serde_json::to_string(&registered_msg).unwrap())).is_err() {
-                                    error!("Failed to send registration confirmation");
-                                    break;
                                 }
-
-                                // Store connection info (use username as key for routing)
-                                {
-                                    let mut connections = state.connections.write().await;
-                                    connections.insert(
-                                        user.clone(),
-                                        ConnectionInfo {
-                                            username: user.clone(),
-                                            connected_at: Instant::now(),
-                                        },
-                                    );
+                            }
+                            WebSocketMessage::Register { username: user, password, public_key } => {
+                                match state.accounts.verify_password(&user, &password).await {
+                                    Ok(()) => {
+                                        if complete_registration(
+                                            &state,
+                                            user,
+                                            public_key,
+                                            &tx,
+                                            tx_for_manager.clone(),
+                                            &mut username,
+                                            &mut guard,
+                                        )
+                                        .await
+                                        {
+                                            break;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let error_msg = WebSocketMessage::Error { message: e.to_string() };
+                                        if tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap())).is_err() {
+                                            break;
+                                        }
+                                    }
                                 }
-
-                                // Register connection in connection manager (use username for routing)
-                                // Clone tx for the manager (we need to keep tx for sending messages)
-                                state.connection_manager.register(user.clone(), tx_for_manager.clone()).await;
-
-                                // Send online users list
-                                let online_users = get_online_users(&state).await;
-                                let response = WebSocketMessage::OnlineUsers {
-                                    users: online_users.clone(),
-                                };
-                                
-                                // Use tx to send message (tx is still available here)
-                                if tx.send(Message::Text(serde_json::to_string(&response).unwrap())).is_err() {
-                                    error!("Failed to send online users");
+                            }
+                            WebSocketMessage::SearchUsers { prefix, limit } => {
+                                let users = crate::signaling::search_usernames(&state, &prefix, limit).await;
+                                let response = WebSocketMessage::SearchResults { users };
+                                if tx.send(Frame::Text(serde_json::to_string(&response).unwrap())).is_err() {
+                                    error!("Failed to send search results");
                                     break;
                                 }
-
-                                // Broadcast to all users that a new user came online
-                                broadcast_user_list(&state).await;
-
-                                info!("User registered: {}", user);
                             }
                             WebSocketMessage::SendMessage { to, encrypted } => {
                                 if let Some(ref from_user) = username {
@@ -149,7 +193,7 @@ pub async fn handle_websocket(socket: WebSocket, state: AppState) {
                                         let error_msg = WebSocketMessage::Error {
                                             message: e,
                                         };
-                                        let _ = tx.send(Message::Text(
+                                        let _ = tx.send(Frame::Text(
                                             serde_json::to_string(&error_msg).unwrap(),
                                         ));
                                     }
@@ -158,11 +202,121 @@ pub async fn handle_websocket(socket: WebSocket, state: AppState) {
                                     let error_msg = WebSocketMessage::Error {
                                         message: "Not authenticated. Please register first.".to_string(),
                                     };
-                                    let _ = tx.send(Message::Text(
+                                    let _ = tx.send(Frame::Text(
                                         serde_json::to_string(&error_msg).unwrap(),
                                     ));
                                 }
                             }
+                            WebSocketMessage::CreateRoom { room } => {
+                                if let Some(ref from_user) = username {
+                                    if let Err(e) = crate::rooms::create_room(&state, &room, from_user).await {
+                                        let error_msg = WebSocketMessage::Error { message: e };
+                                        let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                    } else {
+                                        crate::rooms::broadcast_room_members(&state, &room, &[from_user.clone()]).await;
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
+                            WebSocketMessage::JoinRoom { room } => {
+                                if let Some(ref from_user) = username {
+                                    match crate::rooms::join_room(&state, &room, from_user).await {
+                                        Ok(members) => {
+                                            crate::rooms::broadcast_room_members(&state, &room, &members).await;
+                                        }
+                                        Err(e) => {
+                                            let error_msg = WebSocketMessage::Error { message: e };
+                                            let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                        }
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
+                            WebSocketMessage::LeaveRoom { room } => {
+                                if let Some(ref from_user) = username {
+                                    match crate::rooms::leave_room(&state, &room, from_user).await {
+                                        Ok(members) => {
+                                            crate::rooms::broadcast_room_members(&state, &room, &members).await;
+                                        }
+                                        Err(e) => {
+                                            let error_msg = WebSocketMessage::Error { message: e };
+                                            let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                        }
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
+                            WebSocketMessage::KickMember { room, target } => {
+                                if let Some(ref from_user) = username {
+                                    match crate::rooms::kick_member(&state, &room, from_user, &target).await {
+                                        Ok(members) => {
+                                            crate::rooms::broadcast_room_members(&state, &room, &members).await;
+                                            crate::rooms::notify_member_kicked(&state, &room, &target).await;
+                                        }
+                                        Err(e) => {
+                                            let error_msg = WebSocketMessage::Error { message: e };
+                                            let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                        }
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
+                            WebSocketMessage::DeleteRoom { room } => {
+                                if let Some(ref from_user) = username {
+                                    match crate::rooms::delete_room(&state, &room, from_user).await {
+                                        Ok(former_members) => {
+                                            crate::rooms::notify_room_deleted(&state, &room, &former_members).await;
+                                        }
+                                        Err(e) => {
+                                            let error_msg = WebSocketMessage::Error { message: e };
+                                            let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                        }
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
+                            WebSocketMessage::SendRoomMessage { room, encrypted } => {
+                                if let Some(ref from_user) = username {
+                                    let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+                                    if let Err(e) = crate::rooms::send_room_message(
+                                        &state,
+                                        &room,
+                                        from_user,
+                                        encrypted,
+                                        timestamp,
+                                    )
+                                    .await
+                                    {
+                                        let error_msg = WebSocketMessage::Error { message: e };
+                                        let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                    }
+                                } else {
+                                    let error_msg = WebSocketMessage::Error {
+                                        message: "Not authenticated. Please register first.".to_string(),
+                                    };
+                                    let _ = tx.send(Frame::Text(serde_json::to_string(&error_msg).unwrap()));
+                                }
+                            }
                             _ => {
                                 warn!("Unexpected message type received");
                             }
@@ -173,37 +327,151 @@ pub async fn handle_websocket(socket: WebSocket, state: AppState) {
                     }
                 }
             }
-            Ok(Message::Close(_)) => {
+            Ok(Frame::Close) => {
                 break;
             }
             Err(e) => {
-                error!("WebSocket error: {}", e);
+                error!("Connection error: {}", e);
                 break;
             }
             _ => {}
         }
     }
 
-    // Cleanup on disconnect
+    // Cleanup on disconnect. Dropping `guard` here (or implicitly at the end
+    // of this function) removes this device from the connection manager;
+    // if it was the user's last device, it also clears their AppState
+    // entries and broadcasts the updated roster.
     send_task.abort();
-    
-    if let Some(ref user) = username {
-        {
-            let mut users = state.users.write().await;
-            users.remove(user);
-        }
-        {
-            let mut connections = state.connections.write().await;
-            connections.remove(user);
+    drop(guard);
+}
+
+/// Finish logging a password-verified user in: install them in `AppState`,
+/// register their connection, and push the current roster plus anything
+/// held in their offline mailbox. Shared by `CreateAccount` (brand new
+/// account) and `Register` (returning user) once the password has checked
+/// out. Returns `true` if a send failed and the connection should be closed.
+async fn complete_registration(
+    state: &AppState,
+    user: String,
+    public_key: String,
+    tx: &mpsc::UnboundedSender<Frame>,
+    tx_for_manager: mpsc::UnboundedSender<Frame>,
+    username: &mut Option<String>,
+    guard: &mut Option<ConnectionGuard>,
+) -> bool {
+    // Multi-device: a user already online (e.g. on another device) is
+    // allowed to open a second connection under the same username, so reuse
+    // their existing user id instead of minting a new one.
+    let mut users = state.users.write().await;
+    let user_id = users
+        .get(&user)
+        .map(|existing| existing.user_id.clone())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let session_token = users
+        .get(&user)
+        .map(|existing| existing.session_token.clone())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    *username = Some(user.clone());
+
+    // Store user data keyed by username (for routing)
+    users.insert(
+        user.clone(),
+        UserData {
+            user_id: user_id.clone(),
+            username: user.clone(),
+            public_key: public_key.clone(),
+            last_seen: Instant::now(),
+            session_token,
+        },
+    );
+    drop(users);
+
+    state.usernames.write().await.insert(user.to_lowercase(), user.clone());
+
+    // Send registration success
+    let registered_msg = WebSocketMessage::Registered {
+        user_id: user_id.clone(),
+        username: user.clone(),
+    };
+
+    if tx.send(Frame::Text(serde_json::to_string(&registered_msg).unwrap())).is_err() {
+        error!("Failed to send registration confirmation");
+        return true;
+    }
+
+    // Store connection info (use username as key for routing)
+    {
+        let mut connections = state.connections.write().await;
+        connections.insert(
+            user.clone(),
+            ConnectionInfo {
+                username: user.clone(),
+                connected_at: Instant::now(),
+            },
+        );
+    }
+
+    // Register connection in connection manager (use username for routing).
+    // A user may have several live connections (one per device); each gets
+    // its own connection id so they can come and go independently.
+    let connection_id = state
+        .connection_manager
+        .register(user.clone(), tx_for_manager)
+        .await;
+
+    // From here on, dropping this guard (on any exit path) removes this
+    // device, and once the user's last device disconnects, clears their
+    // entries in AppState.
+    *guard = Some(ConnectionGuard::new(
+        user.clone(),
+        connection_id,
+        state.connection_manager.clone(),
+        state.clone(),
+    ));
+
+    // Send online users list
+    let online_users = get_online_users(state).await;
+    let response = WebSocketMessage::OnlineUsers {
+        users: online_users,
+    };
+
+    if tx.send(Frame::Text(serde_json::to_string(&response).unwrap())).is_err() {
+        error!("Failed to send online users");
+        return true;
+    }
+
+    // Broadcast to all users that a new user came online
+    broadcast_user_list(state).await;
+
+    // Flush anything that arrived in their offline mailbox
+    deliver_mailbox(state, &user, tx).await;
+
+    info!("User registered: {}", user);
+    false
+}
+
+/// Drain a user's offline mailbox and push every held message to them in the
+/// order it was received
+async fn deliver_mailbox(state: &AppState, username: &str, tx: &mpsc::UnboundedSender<Frame>) {
+    let queued = match state.mailboxes.write().await.remove(username) {
+        Some(queued) if !queued.is_empty() => queued,
+        _ => return,
+    };
+
+    for mailbox_message in queued {
+        let message = WebSocketMessage::Message {
+            id: mailbox_message.id,
+            from: mailbox_message.from,
+            to: username.to_string(),
+            encrypted: mailbox_message.encrypted,
+            timestamp: mailbox_message.timestamp,
+        };
+
+        if let Ok(payload) = serde_json::to_string(&message) {
+            let _ = tx.send(Frame::Text(payload));
         }
-        
-        // Remove from connection manager
-        state.connection_manager.remove(user).await;
-        
-        // Notify all users that this user went offline
-        broadcast_user_list(&state).await;
-        
-        info!("User disconnected: {}", user);
     }
 }
 
@@ -214,51 +482,71 @@ async fn get_online_users(state: &AppState) -> Vec<String> {
 }
 
 /// Broadcast updated user list to all connected clients
-async fn broadcast_user_list(state: &AppState) {
+pub(crate) async fn broadcast_user_list(state: &AppState) {
     let online_users = get_online_users(state).await;
     let message = WebSocketMessage::OnlineUsers {
         users: online_users,
     };
     
     if let Ok(message_json) = serde_json::to_string(&message) {
-        state.connection_manager.broadcast(Message::Text(message_json)).await;
+        state.connection_manager.broadcast(Frame::Text(message_json)).await;
     }
 }
 
-/// Forward encrypted message to recipient
+/// Forward encrypted message to recipient, holding it in their offline
+/// mailbox (same store `deliver_mailbox`/`relay_message` use) if they aren't
+/// connected right now rather than dropping it. Errors if `to` is neither
+/// online nor a known account, matching `relay_message`'s 404, so a client
+/// can't mint unbounded mailbox queues for usernames that don't exist -
+/// while a genuinely offline account still gets queued instead of rejected.
 async fn forward_message(
     state: &AppState,
     from: String,
     to: String,
     encrypted: crate::models::EncryptedMessage,
 ) -> Result<(), String> {
-    // Check if recipient is online (users are stored by username)
-    let users = state.users.read().await;
-    if !users.contains_key(&to) {
-        drop(users);
-        return Err("Recipient not found or offline".to_string());
+    let recipient_known = state.users.read().await.contains_key(&to)
+        || state.accounts.account_exists(&to).await.unwrap_or(false);
+
+    if !recipient_known {
+        return Err("Recipient not found".to_string());
     }
-    drop(users);
 
-    // Create message
+    let id = uuid::Uuid::new_v4().to_string();
+    let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+
     let message = WebSocketMessage::Message {
-        id: uuid::Uuid::new_v4().to_string(),
-        from,
+        id: id.clone(),
+        from: from.clone(),
         to: to.clone(),
-        encrypted,
-        timestamp: chrono::Utc::now().timestamp_millis() as u64,
+        encrypted: encrypted.clone(),
+        timestamp,
     };
 
-    // Send via connection manager
     let message_text = serde_json::to_string(&message)
         .map_err(|e| format!("Failed to serialize message: {}", e))?;
-    
-    state
+
+    if state
         .connection_manager
-        .send_to_user(&to, Message::Text(message_text))
+        .send_to_user(&to, Frame::Text(message_text))
         .await
-        .map_err(|e| format!("Failed to send message: {}", e))?;
-    
+        .is_ok()
+    {
+        return Ok(());
+    }
+
+    let mut mailboxes = state.mailboxes.write().await;
+    let mailbox = mailboxes.entry(to).or_insert_with(Default::default);
+    if mailbox.len() >= crate::models::MAILBOX_CAPACITY {
+        mailbox.pop_front();
+    }
+    mailbox.push_back(crate::models::MailboxMessage {
+        id,
+        from,
+        encrypted,
+        timestamp,
+    });
+
     Ok(())
 }
 
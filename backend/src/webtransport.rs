@@ -0,0 +1,158 @@
+use async_trait::async_trait;
+use tracing::{error, info};
+use wtransport::endpoint::IncomingSession;
+use wtransport::{Endpoint, Identity, ServerConfig};
+
+use crate::models::AppState;
+use crate::transport::{Frame, Transport, TransportSender};
+
+/// Path WebTransport clients must request at session setup; anything else
+/// is rejected the same way an unknown axum route would 404
+const WEBTRANSPORT_PATH: &str = "/api/webtransport";
+/// UDP port the WebTransport (HTTP/3) endpoint listens on
+const WEBTRANSPORT_PORT: u16 = 4433;
+
+/// `Transport` over a WebTransport connection's single bidirectional stream,
+/// carrying the same framed messages the WebSocket transport does.
+///
+/// Unlike a WebSocket, a WebTransport stream has no built-in message
+/// boundaries - it's just bytes. `recv` reassembles newline-delimited
+/// messages (written one-per-line by [`WebTransportSender`]) out of however
+/// the underlying reads happen to chunk them, buffering a partial line
+/// until its terminator arrives.
+pub struct WebTransportTransport {
+    stream: wtransport::stream::RecvStream,
+    buffer: Vec<u8>,
+}
+
+impl WebTransportTransport {
+    pub fn new(stream: wtransport::stream::RecvStream) -> Self {
+        Self { stream, buffer: Vec::new() }
+    }
+}
+
+#[async_trait]
+impl Transport for WebTransportTransport {
+    async fn recv(&mut self) -> Option<Result<Frame, String>> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                line.pop(); // drop the trailing '\n'
+                return Some(Ok(Frame::Text(String::from_utf8_lossy(&line).to_string())));
+            }
+
+            let mut chunk = vec![0u8; 64 * 1024];
+            match self.stream.read(&mut chunk).await {
+                Ok(Some(n)) => self.buffer.extend_from_slice(&chunk[..n]),
+                Ok(None) if self.buffer.is_empty() => return None,
+                Ok(None) => {
+                    let remaining = std::mem::take(&mut self.buffer);
+                    return Some(Ok(Frame::Text(String::from_utf8_lossy(&remaining).to_string())));
+                }
+                Err(e) => return Some(Err(e.to_string())),
+            }
+        }
+    }
+}
+
+/// `TransportSender` over a WebTransport connection's single bidirectional
+/// stream. Ping/Pong/Close have no WebTransport-stream equivalent, so they're
+/// no-ops here; the idle timeout still reaps a quiet connection. Each `Text`
+/// message is written with a trailing `\n` so [`WebTransportTransport::recv`]
+/// on the other end can split the byte stream back into messages.
+pub struct WebTransportSender {
+    stream: wtransport::stream::SendStream,
+}
+
+#[async_trait]
+impl TransportSender for WebTransportSender {
+    async fn send(&mut self, frame: Frame) -> Result<(), String> {
+        let payload = match frame {
+            Frame::Text(text) => {
+                let mut payload = text.into_bytes();
+                payload.push(b'\n');
+                payload
+            }
+            Frame::Binary(data) => data,
+            Frame::Ping | Frame::Pong | Frame::Close => return Ok(()),
+        };
+        self.stream.write_all(&payload).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Run the WebTransport (HTTP/3) signaling endpoint alongside the axum
+/// server, accepting sessions at [`WEBTRANSPORT_PATH`] and handing each off
+/// to the same transport-agnostic connection loop the WebSocket uses
+pub async fn serve(state: AppState) {
+    let identity = match Identity::self_signed(["localhost"]) {
+        Ok(identity) => identity,
+        Err(e) => {
+            error!("Failed to generate WebTransport certificate: {}", e);
+            return;
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_bind_default(WEBTRANSPORT_PORT)
+        .with_identity(&identity)
+        .build();
+
+    let endpoint = match Endpoint::server(config) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Failed to start WebTransport endpoint: {}", e);
+            return;
+        }
+    };
+
+    info!("WebTransport signaling endpoint listening on UDP {}", WEBTRANSPORT_PORT);
+
+    loop {
+        let session = endpoint.accept().await;
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_webtransport_session(session, state).await;
+        });
+    }
+}
+
+/// Accept a single WebTransport session and hand its bidirectional stream
+/// off to [`crate::websocket::handle_connection`], same as `ws_handler` does
+/// for a WebSocket upgrade
+async fn handle_webtransport_session(session: IncomingSession, state: AppState) {
+    let session_request = match session.await {
+        Ok(request) => request,
+        Err(e) => {
+            error!("WebTransport session request failed: {}", e);
+            return;
+        }
+    };
+
+    if session_request.path() != WEBTRANSPORT_PATH {
+        session_request.not_found().await;
+        return;
+    }
+
+    let connection = match session_request.accept().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("WebTransport handshake failed: {}", e);
+            return;
+        }
+    };
+
+    let (send_stream, recv_stream) = match connection.accept_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            error!("WebTransport stream negotiation failed: {}", e);
+            return;
+        }
+    };
+
+    crate::websocket::handle_connection(
+        WebTransportTransport::new(recv_stream),
+        WebTransportSender { stream: send_stream },
+        state,
+    )
+    .await;
+}